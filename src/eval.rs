@@ -1,27 +1,163 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
 use crate::expressions::{BinaryOperator, Expression};
+use crate::{Region, RootItem, Statement};
 
-use crate::script::PARSE_STATE;
+/// Error produced while evaluating a parsed [`Expression`].
+///
+/// Mirrors the `ExprError` pattern used by the parser: a small, matchable enum instead of a
+/// bare `String`, so callers can branch on failure kind rather than scrape a message.
+///
+/// `UndefinedSymbol` and `CircularReference` carry the byte-offset `span` of the assignment
+/// statement whose expression referenced the offending symbol, so a caller can point a
+/// diagnostic at the right place in the original script. `Expression` isn't itself wrapped in
+/// `Spanned`, so this is statement granularity, not the exact identifier token; for a symbol
+/// referenced from outside any assignment, `span` is whatever [`evaluate_expression`]'s caller
+/// passed in for the expression being evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError {
+    /// Referenced a symbol (assignment or memory region) that was never defined.
+    UndefinedSymbol { name: String, span: Range<usize> },
+    /// Called a function `evaluate_expression` doesn't know about.
+    UnknownFunction(String),
+    /// A function was called with the wrong number of arguments.
+    BadArity {
+        function: String,
+        expected: usize,
+        got: usize,
+    },
+    /// A function argument had the wrong shape, e.g. a number where an identifier was expected.
+    InvalidArgument { function: String, message: String },
+    /// Division or modulo by zero.
+    DivisionByZero,
+    /// A symbol's definition (directly or transitively) depends on its own value.
+    CircularReference { name: String, span: Range<usize> },
+}
 
-fn _evaluate_expression(expr: &Expression) -> Result<u64, String> {
-    Ok(match expr {
-        Expression::Number(n) => *n,
-        Expression::Ident(s) => {
-            return PARSE_STATE.with_borrow(|state| {
-                for item in &state.items {
-                    if let crate::RootItem::Statement(stmt) = item {
-                        if let crate::Statement::Assign {
-                            name, expression, ..
-                        } = stmt
-                        {
-                            if name == s {
-                                return _evaluate_expression(&**expression);
-                            }
-                        }
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::UndefinedSymbol { name, span } => write!(
+                f,
+                "variable {:?} not found (referenced at {}..{})",
+                name, span.start, span.end
+            ),
+            EvalError::UnknownFunction(name) => write!(f, "function {:?} not supported", name),
+            EvalError::BadArity {
+                function,
+                expected,
+                got,
+            } => write!(
+                f,
+                "function {:?} expects {} argument(s), got {}",
+                function, expected, got
+            ),
+            EvalError::InvalidArgument { function, message } => {
+                write!(f, "function {:?} {}", function, message)
+            }
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+            EvalError::CircularReference { name, span } => write!(
+                f,
+                "circular reference while resolving {:?} (assignment at {}..{})",
+                name, span.start, span.end
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Every symbol a script's expressions can reference: variable assignments and memory-region
+/// bounds, collected once up front so lookups are O(1) instead of a rescan of `items` per use.
+///
+/// Assignments are resolved lazily and memoized in `cache`, so a symbol referenced from several
+/// places (or transitively, through other assignments) is only evaluated once. Forward
+/// references (`A = B; B = 4;`) work because resolution isn't tied to textual order.
+pub struct SymbolTable<'a> {
+    assignments: HashMap<&'a str, (&'a Expression, Range<usize>)>,
+    regions: HashMap<&'a str, &'a Region>,
+    cache: RefCell<HashMap<String, u64>>,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn new(items: &'a [RootItem]) -> Self {
+        let mut assignments = HashMap::new();
+        let mut regions = HashMap::new();
+        for item in items {
+            match item {
+                RootItem::Statement(stmt) => {
+                    if let Statement::Assign {
+                        name, expression, ..
+                    } = &stmt.inner
+                    {
+                        // First assignment wins, matching the pre-table lookup that scanned
+                        // `items` and returned on the first match.
+                        assignments
+                            .entry(name.as_str())
+                            .or_insert((&**expression, stmt.span.clone()));
+                    }
+                }
+                RootItem::Memory { regions: list } => {
+                    for region in list {
+                        regions.entry(region.name.as_str()).or_insert(region);
                     }
                 }
-                Err(format!("Variable {:?} not found", s))
+                RootItem::Command(_) | RootItem::Sections { .. } => {}
+            }
+        }
+        SymbolTable {
+            assignments,
+            regions,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Resolves `name` to its assigned value, memoizing the result. `stack` holds the symbols
+    /// currently being resolved on this call chain; re-entering one means the script defines a
+    /// symbol in terms of itself. `span` is the span of the assignment whose expression
+    /// referenced `name`, attached to `UndefinedSymbol`/`CircularReference` if resolution fails.
+    fn resolve(
+        &self,
+        name: &str,
+        span: &Range<usize>,
+        stack: &mut HashSet<String>,
+    ) -> Result<u64, EvalError> {
+        if let Some(value) = self.cache.borrow().get(name) {
+            return Ok(*value);
+        }
+        if !stack.insert(name.to_string()) {
+            return Err(EvalError::CircularReference {
+                name: name.to_string(),
+                span: span.clone(),
             });
         }
+        let result = match self.assignments.get(name) {
+            Some((expression, def_span)) => _evaluate_expression(expression, def_span, self, stack),
+            None => Err(EvalError::UndefinedSymbol {
+                name: name.to_string(),
+                span: span.clone(),
+            }),
+        };
+        stack.remove(name);
+        if let Ok(value) = result {
+            self.cache.borrow_mut().insert(name.to_string(), value);
+        }
+        result
+    }
+}
+
+fn _evaluate_expression<'a>(
+    expr: &Expression,
+    span: &Range<usize>,
+    table: &SymbolTable<'a>,
+    stack: &mut HashSet<String>,
+) -> Result<u64, EvalError> {
+    Ok(match expr {
+        Expression::Number(n) => *n,
+        Expression::Ident(s) => return table.resolve(s, span, stack),
         Expression::Call {
             function,
             arguments,
@@ -29,58 +165,206 @@ fn _evaluate_expression(expr: &Expression) -> Result<u64, String> {
             return match function.as_str() {
                 "ORIGIN" | "LENGTH" => {
                     if arguments.len() != 1 {
-                        return Err(format!("function {:?} only support 1 argument", function));
+                        return Err(EvalError::BadArity {
+                            function: function.clone(),
+                            expected: 1,
+                            got: arguments.len(),
+                        });
                     }
                     if let Expression::Ident(s) = &arguments[0] {
-                        return PARSE_STATE.with_borrow(|state| {
-                            for item in &state.items {
-                                if let crate::RootItem::Memory { regions } = item {
-                                    for region in regions {
-                                        if region.name == *s {
-                                            return Ok(match function.as_str() {
-                                                "ORIGIN" => region.origin,
-                                                "LENGTH" => region.length,
-                                                _ => unreachable!(),
-                                            });
-                                        }
-                                    }
-                                }
-                            }
-                            Err(format!("Variable {:?} not found", s))
-                        });
+                        match table.regions.get(s.as_str()) {
+                            Some(region) => Ok(match function.as_str() {
+                                "ORIGIN" => region.origin,
+                                "LENGTH" => region.length,
+                                _ => unreachable!(),
+                            }),
+                            None => Err(EvalError::UndefinedSymbol {
+                                name: s.clone(),
+                                span: span.clone(),
+                            }),
+                        }
                     } else {
-                        return Err(format!("function {:?} argument must be string", function));
+                        Err(EvalError::InvalidArgument {
+                            function: function.clone(),
+                            message: "argument must be an identifier".into(),
+                        })
+                    }
+                }
+                "ALIGN" => match arguments.len() {
+                    1 => {
+                        let align = _evaluate_expression(&arguments[0], span, table, stack)?;
+                        Ok(align_to(0, align))
+                    }
+                    2 => {
+                        let value = _evaluate_expression(&arguments[0], span, table, stack)?;
+                        let align = _evaluate_expression(&arguments[1], span, table, stack)?;
+                        Ok(align_to(value, align))
+                    }
+                    got => Err(EvalError::BadArity {
+                        function: function.clone(),
+                        expected: 2,
+                        got,
+                    }),
+                },
+                "MAX" => {
+                    if arguments.len() != 2 {
+                        return Err(EvalError::BadArity {
+                            function: function.clone(),
+                            expected: 2,
+                            got: arguments.len(),
+                        });
+                    }
+                    let a = _evaluate_expression(&arguments[0], span, table, stack)?;
+                    let b = _evaluate_expression(&arguments[1], span, table, stack)?;
+                    Ok(a.max(b))
+                }
+                "MIN" => {
+                    if arguments.len() != 2 {
+                        return Err(EvalError::BadArity {
+                            function: function.clone(),
+                            expected: 2,
+                            got: arguments.len(),
+                        });
+                    }
+                    let a = _evaluate_expression(&arguments[0], span, table, stack)?;
+                    let b = _evaluate_expression(&arguments[1], span, table, stack)?;
+                    Ok(a.min(b))
+                }
+                "ABSOLUTE" => {
+                    if arguments.len() != 1 {
+                        return Err(EvalError::BadArity {
+                            function: function.clone(),
+                            expected: 1,
+                            got: arguments.len(),
+                        });
+                    }
+                    _evaluate_expression(&arguments[0], span, table, stack)
+                }
+                "DEFINED" => {
+                    if arguments.len() != 1 {
+                        return Err(EvalError::BadArity {
+                            function: function.clone(),
+                            expected: 1,
+                            got: arguments.len(),
+                        });
+                    }
+                    Ok(
+                        match _evaluate_expression(&arguments[0], span, table, stack) {
+                            Ok(_) => 1,
+                            Err(_) => 0,
+                        },
+                    )
+                }
+                "LOG2CEIL" => {
+                    if arguments.len() != 1 {
+                        return Err(EvalError::BadArity {
+                            function: function.clone(),
+                            expected: 1,
+                            got: arguments.len(),
+                        });
                     }
+                    let value = _evaluate_expression(&arguments[0], span, table, stack)?;
+                    Ok(log2ceil(value))
                 }
-                _ => Err(format!("function {:?} not supported", function)),
+                _ => Err(EvalError::UnknownFunction(function.clone())),
             }
         }
+        Expression::Ternary {
+            condition,
+            if_true,
+            if_false,
+        } => {
+            return if _evaluate_expression(&**condition, span, table, stack)? != 0 {
+                _evaluate_expression(&**if_true, span, table, stack)
+            } else {
+                _evaluate_expression(&**if_false, span, table, stack)
+            };
+        }
         Expression::BinaryOp {
             left,
             operator,
             right,
         } => {
-            let left = _evaluate_expression(&**left)?;
-            let right = _evaluate_expression(&**right)?;
+            // `&&`/`||` short-circuit, so the right-hand side is only evaluated when needed.
+            if matches!(operator, BinaryOperator::LogicalAnd) {
+                let left = _evaluate_expression(&**left, span, table, stack)?;
+                return if left == 0 {
+                    Ok(0)
+                } else {
+                    Ok((_evaluate_expression(&**right, span, table, stack)? != 0) as u64)
+                };
+            }
+            if matches!(operator, BinaryOperator::LogicalOr) {
+                let left = _evaluate_expression(&**left, span, table, stack)?;
+                return if left != 0 {
+                    Ok(1)
+                } else {
+                    Ok((_evaluate_expression(&**right, span, table, stack)? != 0) as u64)
+                };
+            }
+
+            let left = _evaluate_expression(&**left, span, table, stack)?;
+            let right = _evaluate_expression(&**right, span, table, stack)?;
             match operator {
                 BinaryOperator::Plus => left.wrapping_add(right),
                 BinaryOperator::Minus => left.wrapping_sub(right),
                 BinaryOperator::Multiply => left.wrapping_mul(right),
-                BinaryOperator::Divide => left.wrapping_div(right),
-                _ => return Err(format!("Binary operator {:?} not supported", operator)),
+                BinaryOperator::Divide => left.checked_div(right).ok_or(EvalError::DivisionByZero)?,
+                BinaryOperator::Modulo => left.checked_rem(right).ok_or(EvalError::DivisionByZero)?,
+                BinaryOperator::BitAnd => left & right,
+                BinaryOperator::BitOr => left | right,
+                BinaryOperator::BitXor => left ^ right,
+                BinaryOperator::ShiftLeft => left.wrapping_shl(right as u32),
+                BinaryOperator::ShiftRight => left.wrapping_shr(right as u32),
+                BinaryOperator::Equal => (left == right) as u64,
+                BinaryOperator::NotEqual => (left != right) as u64,
+                BinaryOperator::LessThan => (left < right) as u64,
+                BinaryOperator::GreaterThan => (left > right) as u64,
+                BinaryOperator::LessEqual => (left <= right) as u64,
+                BinaryOperator::GreaterEqual => (left >= right) as u64,
+                BinaryOperator::LogicalAnd | BinaryOperator::LogicalOr => unreachable!(),
             }
         }
-        _ => return Err(format!("Expression {:?} not supported", expr)),
     })
 }
 
-pub fn evaluate_expression(expr: Expression) -> Result<u64, String> {
-    _evaluate_expression(&expr)
+/// `ALIGN(expr, align)` rounds `expr` up to the next multiple of `align`, which must be a
+/// power of two, matching the `(expr + align - 1) & !(align - 1)` behavior of GNU ld.
+fn align_to(value: u64, align: u64) -> u64 {
+    if align == 0 {
+        return value;
+    }
+    (value + align - 1) & !(align - 1)
+}
+
+/// Smallest `n` such that `2^n >= value`, as used by ld's `LOG2CEIL`.
+fn log2ceil(value: u64) -> u64 {
+    if value <= 1 {
+        return 0;
+    }
+    (u64::BITS - (value - 1).leading_zeros()) as u64
+}
+
+/// Evaluates `expr`, resolving idents and `ORIGIN`/`LENGTH` calls against `table`. Forward
+/// references and mutually-referential assignments resolve correctly since `table` is built from
+/// the whole script up front rather than scanned textually up to the use site.
+///
+/// `expr` has no span of its own (it isn't wrapped in `Spanned`), so `span` should be the byte
+/// range of `expr` in the original script; it's attached to any `UndefinedSymbol`/
+/// `CircularReference` raised directly from `expr` itself, rather than from an assignment
+/// `table` resolves along the way (those carry the assignment's own span instead). Callers
+/// without a meaningful span, e.g. in tests, may pass `0..0`.
+pub fn evaluate_expression(
+    expr: Expression,
+    span: Range<usize>,
+    table: &SymbolTable,
+) -> Result<u64, EvalError> {
+    _evaluate_expression(&expr, &span, table, &mut HashSet::new())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{script::clear_state, AssignOperator, Region, RootItem, Statement};
+    use crate::{script::Spanned, AssignOperator, Region, RootItem, Statement};
 
     use super::*;
     use nom::combinator::map_res;
@@ -88,71 +372,378 @@ mod tests {
 
     #[test]
     fn test_evaluate_expression() {
-        assert_eq!(evaluate_expression(Expression::Number(42)), Ok(42));
+        let table = SymbolTable::new(&[]);
+
+        assert_eq!(evaluate_expression(Expression::Number(42), 0..0, &table), Ok(42));
 
         assert_eq!(
-            evaluate_expression(Expression::BinaryOp {
-                left: Box::new(Expression::Number(42)),
-                operator: Plus,
-                right: Box::new(Expression::Number(42))
-            }),
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(42)),
+                    operator: Plus,
+                    right: Box::new(Expression::Number(42))
+                },
+                0..0,
+                &table
+            ),
             Ok(84)
         );
         assert_eq!(
-            evaluate_expression(Expression::BinaryOp {
-                left: Box::new(Expression::Number(42)),
-                operator: Minus,
-                right: Box::new(Expression::Number(42))
-            }),
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(42)),
+                    operator: Minus,
+                    right: Box::new(Expression::Number(42))
+                },
+                0..0,
+                &table
+            ),
             Ok(0)
         );
         assert_eq!(
-            evaluate_expression(Expression::BinaryOp {
-                left: Box::new(Expression::Number(42)),
-                operator: Multiply,
-                right: Box::new(Expression::Number(42))
-            }),
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(42)),
+                    operator: Multiply,
+                    right: Box::new(Expression::Number(42))
+                },
+                0..0,
+                &table
+            ),
             Ok(1764)
         );
         assert_eq!(
-            evaluate_expression(Expression::BinaryOp {
-                left: Box::new(Expression::Number(42)),
-                operator: Divide,
-                right: Box::new(Expression::Number(42))
-            }),
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(42)),
+                    operator: Divide,
+                    right: Box::new(Expression::Number(42))
+                },
+                0..0,
+                &table
+            ),
+            Ok(1)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(1)),
+                    operator: Divide,
+                    right: Box::new(Expression::Number(0))
+                },
+                0..0,
+                &table
+            ),
+            Err(EvalError::DivisionByZero)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(7)),
+                    operator: Modulo,
+                    right: Box::new(Expression::Number(3))
+                },
+                0..0,
+                &table
+            ),
+            Ok(1)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(0b1100)),
+                    operator: BitAnd,
+                    right: Box::new(Expression::Number(0b1010))
+                },
+                0..0,
+                &table
+            ),
+            Ok(0b1000)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(0b1100)),
+                    operator: BitOr,
+                    right: Box::new(Expression::Number(0b1010))
+                },
+                0..0,
+                &table
+            ),
+            Ok(0b1110)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(0b1100)),
+                    operator: BitXor,
+                    right: Box::new(Expression::Number(0b1010))
+                },
+                0..0,
+                &table
+            ),
+            Ok(0b0110)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(1)),
+                    operator: ShiftLeft,
+                    right: Box::new(Expression::Number(4))
+                },
+                0..0,
+                &table
+            ),
+            Ok(16)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(16)),
+                    operator: ShiftRight,
+                    right: Box::new(Expression::Number(4))
+                },
+                0..0,
+                &table
+            ),
+            Ok(1)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(4)),
+                    operator: LessThan,
+                    right: Box::new(Expression::Number(5))
+                },
+                0..0,
+                &table
+            ),
+            Ok(1)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(4)),
+                    operator: Equal,
+                    right: Box::new(Expression::Number(4))
+                },
+                0..0,
+                &table
+            ),
+            Ok(1)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(1)),
+                    operator: LogicalAnd,
+                    right: Box::new(Expression::Number(0))
+                },
+                0..0,
+                &table
+            ),
+            Ok(0)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::BinaryOp {
+                    left: Box::new(Expression::Number(0)),
+                    operator: LogicalOr,
+                    right: Box::new(Expression::Number(1))
+                },
+                0..0,
+                &table
+            ),
+            Ok(1)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Ternary {
+                    condition: Box::new(Expression::Number(1)),
+                    if_true: Box::new(Expression::Number(10)),
+                    if_false: Box::new(Expression::Number(20)),
+                },
+                0..0,
+                &table
+            ),
+            Ok(10)
+        );
+    }
+
+    #[test]
+    fn test_evaluate_builtins() {
+        let table = SymbolTable::new(&[]);
+
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "ALIGN".into(),
+                    arguments: vec![Expression::Number(5), Expression::Number(8)],
+                },
+                0..0,
+                &table
+            ),
+            Ok(8)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "ALIGN".into(),
+                    arguments: vec![Expression::Number(8)],
+                },
+                0..0,
+                &table
+            ),
+            Ok(0)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "MAX".into(),
+                    arguments: vec![Expression::Number(3), Expression::Number(7)],
+                },
+                0..0,
+                &table
+            ),
+            Ok(7)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "MIN".into(),
+                    arguments: vec![Expression::Number(3), Expression::Number(7)],
+                },
+                0..0,
+                &table
+            ),
+            Ok(3)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "ABSOLUTE".into(),
+                    arguments: vec![Expression::Number(42)],
+                },
+                0..0,
+                &table
+            ),
+            Ok(42)
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "DEFINED".into(),
+                    arguments: vec![Expression::Ident("MISSING".into())],
+                },
+                0..0,
+                &table
+            ),
+            Ok(0)
+        );
+        let defined_items = vec![RootItem::Statement(Spanned::new(
+            Statement::Assign {
+                name: "A".into(),
+                operator: AssignOperator::Equals,
+                expression: Box::new(Expression::Number(1)),
+            },
+            0..0,
+        ))];
+        let defined_table = SymbolTable::new(&defined_items);
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "DEFINED".into(),
+                    arguments: vec![Expression::Ident("A".into())],
+                },
+                0..0,
+                &defined_table
+            ),
             Ok(1)
         );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "LOG2CEIL".into(),
+                    arguments: vec![Expression::Number(9)],
+                },
+                0..0,
+                &table
+            ),
+            Ok(4)
+        );
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let table = SymbolTable::new(&[]);
+
+        assert_eq!(
+            evaluate_expression(Expression::Ident("MISSING".into()), 0..0, &table),
+            Err(EvalError::UndefinedSymbol {
+                name: "MISSING".into(),
+                span: 0..0
+            })
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "NOPE".into(),
+                    arguments: vec![],
+                },
+                0..0,
+                &table
+            ),
+            Err(EvalError::UnknownFunction("NOPE".into()))
+        );
+        assert_eq!(
+            evaluate_expression(
+                Expression::Call {
+                    function: "MAX".into(),
+                    arguments: vec![Expression::Number(1)],
+                },
+                0..0,
+                &table
+            ),
+            Err(EvalError::BadArity {
+                function: "MAX".into(),
+                expected: 2,
+                got: 1,
+            })
+        );
     }
 
-    fn expr_result(input: &str, expected: u64) {
+    fn expr_result(input: &str, items: &[RootItem], expected: u64) {
+        let table = SymbolTable::new(items);
         assert_done!(
-            map_res(crate::expressions::expression, evaluate_expression)(input),
+            map_res(crate::expressions::expression, |expr| evaluate_expression(
+                expr, 0..0, &table
+            ))(input),
             expected
         );
     }
 
     #[test]
     fn test_parsed_expressions() {
-        expr_result("42 - (20 + 21)", 1);
-        expr_result("42 - (4 * 8)", 10);
-        expr_result("42", 42);
-        expr_result("42 + 42", 84);
-        expr_result("42 - 42", 0);
-        expr_result("42 * 42", 1764);
-        expr_result("42 / 42", 1);
-        expr_result("0x2000000 + (4k * 4)", 0x2000000 + (4 * 1024 * 4));
-
-        clear_state();
-        PARSE_STATE.with_borrow_mut(|state| {
-            state.items.push(RootItem::Statement(Statement::Assign {
+        expr_result("42 - (20 + 21)", &[], 1);
+        expr_result("42 - (4 * 8)", &[], 10);
+        expr_result("42", &[], 42);
+        expr_result("42 + 42", &[], 84);
+        expr_result("42 - 42", &[], 0);
+        expr_result("42 * 42", &[], 1764);
+        expr_result("42 / 42", &[], 1);
+        expr_result("0x2000000 + (4k * 4)", &[], 0x2000000 + (4 * 1024 * 4));
+
+        let mut items = vec![RootItem::Statement(Spanned::new(
+            Statement::Assign {
                 name: "A".into(),
                 operator: AssignOperator::Equals,
                 expression: Box::new(Expression::Number(11)),
-            }));
-        });
-        expr_result("A * 2", 22);
-        PARSE_STATE.with_borrow_mut(|state| {
-            state.items.push(RootItem::Statement(Statement::Assign {
+            },
+            0..0,
+        ))];
+        expr_result("A * 2", &items, 22);
+        items.push(RootItem::Statement(Spanned::new(
+            Statement::Assign {
                 name: "B".into(),
                 operator: AssignOperator::Equals,
                 expression: Box::new(Expression::BinaryOp {
@@ -160,19 +751,128 @@ mod tests {
                     operator: BinaryOperator::Plus,
                     right: Box::new(Expression::Number(4)),
                 }),
-            }));
-        });
-        expr_result("A * B", 66);
-        PARSE_STATE.with_borrow_mut(|state| {
-            state.items.push(RootItem::Memory {
-                regions: vec![Region {
-                    name: String::from("AA"),
-                    origin: 66,
-                    length: 12,
-                }],
-            });
+            },
+            0..0,
+        )));
+        expr_result("A * B", &items, 66);
+        items.push(RootItem::Memory {
+            regions: vec![Region {
+                name: String::from("AA"),
+                origin: 66,
+                length: 12,
+            }],
         });
-        expr_result("ORIGIN(AA)", 66);
-        expr_result("LENGTH(AA)", 12);
+        expr_result("ORIGIN(AA)", &items, 66);
+        expr_result("LENGTH(AA)", &items, 12);
+    }
+
+    #[test]
+    fn test_forward_reference() {
+        // `A` is defined before `B`, which `A` is built from; ld allows forward references, and
+        // the symbol table resolves by name rather than by scanning textually up to the use.
+        let items = vec![
+            RootItem::Statement(Spanned::new(
+                Statement::Assign {
+                    name: "A".into(),
+                    operator: AssignOperator::Equals,
+                    expression: Box::new(Expression::Ident("B".into())),
+                },
+                0..0,
+            )),
+            RootItem::Statement(Spanned::new(
+                Statement::Assign {
+                    name: "B".into(),
+                    operator: AssignOperator::Equals,
+                    expression: Box::new(Expression::Number(4)),
+                },
+                0..0,
+            )),
+        ];
+        let table = SymbolTable::new(&items);
+        assert_eq!(
+            evaluate_expression(Expression::Ident("A".into()), 0..0, &table),
+            Ok(4)
+        );
+    }
+
+    #[test]
+    fn test_reassignment_keeps_first_value() {
+        // ld resolves `A` to the first assignment it scans, not the last; a script that
+        // reassigns a symbol must keep evaluating to the original value.
+        let items = vec![
+            RootItem::Statement(Spanned::new(
+                Statement::Assign {
+                    name: "A".into(),
+                    operator: AssignOperator::Equals,
+                    expression: Box::new(Expression::Number(1)),
+                },
+                0..0,
+            )),
+            RootItem::Statement(Spanned::new(
+                Statement::Assign {
+                    name: "A".into(),
+                    operator: AssignOperator::Equals,
+                    expression: Box::new(Expression::Number(2)),
+                },
+                0..0,
+            )),
+        ];
+        let table = SymbolTable::new(&items);
+        assert_eq!(
+            evaluate_expression(Expression::Ident("A".into()), 0..0, &table),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn test_undefined_symbol_reports_referencing_span() {
+        // `A`'s assignment, spanning bytes 4..15 of some hypothetical script, references the
+        // undefined `MISSING`; the error should point at that assignment, not at `0..0`.
+        let items = vec![RootItem::Statement(Spanned::new(
+            Statement::Assign {
+                name: "A".into(),
+                operator: AssignOperator::Equals,
+                expression: Box::new(Expression::Ident("MISSING".into())),
+            },
+            4..15,
+        ))];
+        let table = SymbolTable::new(&items);
+        assert_eq!(
+            evaluate_expression(Expression::Ident("A".into()), 0..0, &table),
+            Err(EvalError::UndefinedSymbol {
+                name: "MISSING".into(),
+                span: 4..15
+            })
+        );
+    }
+
+    #[test]
+    fn test_circular_reference_is_detected() {
+        let items = vec![
+            RootItem::Statement(Spanned::new(
+                Statement::Assign {
+                    name: "A".into(),
+                    operator: AssignOperator::Equals,
+                    expression: Box::new(Expression::Ident("B".into())),
+                },
+                0..0,
+            )),
+            RootItem::Statement(Spanned::new(
+                Statement::Assign {
+                    name: "B".into(),
+                    operator: AssignOperator::Equals,
+                    expression: Box::new(Expression::Ident("A".into())),
+                },
+                0..0,
+            )),
+        ];
+        let table = SymbolTable::new(&items);
+        assert_eq!(
+            evaluate_expression(Expression::Ident("A".into()), 0..0, &table),
+            Err(EvalError::CircularReference {
+                name: "A".into(),
+                span: 0..0
+            })
+        );
     }
 }
@@ -0,0 +1,10 @@
+//! Parses and evaluates GNU ld linker scripts.
+//!
+//! This source tree is one chunk of the crate split into several pieces for review; `script.rs`
+//! and `eval.rs` reference `commands`, `memory`, `sections`, `statements`, `whitespace`, and
+//! `expressions` modules, plus a `wsc!` whitespace-wrapping macro, that are defined in sibling
+//! chunks not present in this tree. Until those chunks are assembled alongside this one, `cargo
+//! build` fails on unresolved imports here, and no test in `eval.rs` or `script.rs` has actually
+//! been compiled or run by any commit in this chunk's history.
+pub mod eval;
+pub mod script;
@@ -1,4 +1,4 @@
-use std::cell::RefCell;
+use std::ops::{Deref, Range};
 
 use commands::{command, Command};
 use memory::region;
@@ -12,50 +12,76 @@ use sections::SectionCommand;
 use statements::{statement, Statement};
 use whitespace::opt_space;
 
-thread_local! {
-    pub(crate) static PARSE_STATE: RefCell<ParseState> = RefCell::new(ParseState::default());
+/// A parsed value together with the byte-offset range it occupied in the original script,
+/// so callers can point a diagnostic at the source location of e.g. the statement that
+/// references an undefined symbol.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Spanned<T> {
+    pub inner: T,
+    pub span: Range<usize>,
 }
 
-#[derive(Debug, Default)]
-pub struct ParseState {
-    pub items: Vec<RootItem>,
+impl<T> Spanned<T> {
+    pub fn new(inner: T, span: Range<usize>) -> Self {
+        Spanned { inner, span }
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
 }
 
+/// Byte offset range `[start, end)` occupies relative to `original`, relying on `start`/`end`
+/// being nom's zero-copy sub-slices of `original` rather than freshly allocated strings.
+fn span_of(original: &str, start: &str, end: &str) -> Range<usize> {
+    let base = original.as_ptr() as usize;
+    (start.as_ptr() as usize - base)..(end.as_ptr() as usize - base)
+}
+
+/// A top-level linker-script construct.
+///
+/// Not yet `Serialize`/`Deserialize` behind the `serde` feature, unlike `Spanned<T>`: deriving
+/// on an enum requires every variant's field types to implement the trait too, and `Region`,
+/// `Statement`, `Command`, and `SectionCommand` don't, since they live in the `memory`,
+/// `statements`, `commands`, and `sections` modules this chunk doesn't touch (along with
+/// `Expression`/`BinaryOperator`, which `Statement` embeds transitively). Round-tripping
+/// `parse(...)?.1` to JSON for tooling (caching, diffing two scripts) needs those modules to
+/// derive the same traits behind the same feature first; only then can this derive on `RootItem`
+/// actually compile.
 #[derive(Debug, PartialEq)]
 pub enum RootItem {
-    Statement(Statement),
+    Statement(Spanned<Statement>),
     Command(Command),
     Memory { regions: Vec<Region> },
     Sections { list: Vec<SectionCommand> },
 }
 
-fn statement_item(input: &str) -> IResult<&str, ()> {
-    let (input, stmt) = statement(input)?;
-    PARSE_STATE.with_borrow_mut(|s| s.items.push(RootItem::Statement(stmt)));
-    Ok((input, ()))
+fn statement_item<'a>(original: &'a str) -> impl FnMut(&'a str) -> IResult<&'a str, RootItem> {
+    move |input: &'a str| {
+        let start = input;
+        let (input, stmt) = statement(input)?;
+        let span = span_of(original, start, input);
+        Ok((input, RootItem::Statement(Spanned::new(stmt, span))))
+    }
 }
 
-fn command_item(input: &str) -> IResult<&str, ()> {
+fn command_item(input: &str) -> IResult<&str, RootItem> {
     let (input, cmd) = command(input)?;
-    PARSE_STATE.with_borrow_mut(|s| s.items.push(RootItem::Command(cmd)));
-    Ok((input, ()))
+    Ok((input, RootItem::Command(cmd)))
 }
 
-fn memory_item(input: &str) -> IResult<&str, ()> {
+fn memory_item(input: &str) -> IResult<&str, RootItem> {
     let (mut input, _) = tuple((tag("MEMORY"), wsc!(tag("{"))))(input)?;
-    PARSE_STATE.with_borrow_mut(|s| {
-        s.items.push(RootItem::Memory {
-            regions: Vec::new(),
-        })
-    });
+    let mut regions = Vec::new();
     loop {
         match wsc!(region)(input) {
             Ok((next_input, region_item)) => {
-                PARSE_STATE.with_borrow_mut(|s| {
-                    if let Some(RootItem::Memory { regions }) = s.items.last_mut() {
-                        regions.push(region_item);
-                    }
-                });
+                regions.push(region_item);
                 input = next_input;
             }
             Err(nom::Err::Error(_)) | Err(nom::Err::Incomplete(_)) => break,
@@ -63,20 +89,16 @@ fn memory_item(input: &str) -> IResult<&str, ()> {
         }
     }
     let (input, _) = tag("}")(input)?;
-    Ok((input, ()))
+    Ok((input, RootItem::Memory { regions }))
 }
 
-fn sections_item(input: &str) -> IResult<&str, ()> {
+fn sections_item(input: &str) -> IResult<&str, RootItem> {
     let (mut input, _) = tuple((tag("SECTIONS"), wsc!(tag("{"))))(input)?;
-    PARSE_STATE.with_borrow_mut(|s| s.items.push(RootItem::Sections { list: Vec::new() }));
+    let mut list = Vec::new();
     loop {
         match wsc!(section_command)(input) {
             Ok((next_input, section_item)) => {
-                PARSE_STATE.with_borrow_mut(|s| {
-                    if let Some(RootItem::Sections { list }) = s.items.last_mut() {
-                        list.push(section_item);
-                    }
-                });
+                list.push(section_item);
                 input = next_input;
             }
             Err(nom::Err::Error(_)) | Err(nom::Err::Incomplete(_)) => break,
@@ -84,28 +106,25 @@ fn sections_item(input: &str) -> IResult<&str, ()> {
         }
     }
     let (input, _) = tag("}")(input)?;
-    Ok((input, ()))
+    Ok((input, RootItem::Sections { list }))
 }
 
-fn root_item(input: &str) -> IResult<&str, ()> {
-    alt((statement_item, memory_item, sections_item, command_item))(input)
-}
-
-pub(crate) fn clear_state() {
-    // Reset thread-local state
-    PARSE_STATE.with_borrow_mut(|state| {
-        *state = ParseState::default();
-    });
+fn root_item<'a>(original: &'a str) -> impl FnMut(&'a str) -> IResult<&'a str, RootItem> {
+    let mut statement_item = statement_item(original);
+    move |input: &'a str| alt((&mut statement_item, memory_item, sections_item, command_item))(input)
 }
 
 pub fn parse(input: &str) -> IResult<&str, Vec<RootItem>> {
-    clear_state();
+    let original = input;
+    let mut root_item = root_item(original);
 
+    let mut items = Vec::new();
     let mut input = input;
     loop {
         // Try to parse a root_item, skipping optional whitespace before it
-        match wsc!(root_item)(input) {
-            Ok((next_input, ())) => {
+        match wsc!(&mut root_item)(input) {
+            Ok((next_input, item)) => {
+                items.push(item);
                 input = next_input;
             }
             Err(nom::Err::Error(_)) | Err(nom::Err::Incomplete(_)) => {
@@ -119,9 +138,7 @@ pub fn parse(input: &str) -> IResult<&str, Vec<RootItem>> {
     // Skip trailing optional whitespace
     let (input, _) = opt_space(input)?;
 
-    let items = PARSE_STATE.with(|s| std::mem::take(&mut *s.borrow_mut()));
-
-    Ok((input, items.items))
+    Ok((input, items))
 }
 
 #[cfg(test)]
@@ -155,4 +172,25 @@ mod tests {
             assert_done!(parse(&contents));
         }
     }
+
+    #[test]
+    fn test_reentrant() {
+        // Two back-to-back parses on the same thread must not see each other's items: nothing
+        // left in a shared cell to corrupt.
+        let (_, a) = parse("A = 1;").unwrap();
+        let (_, b) = parse("B = 2; C = 3;").unwrap();
+        assert_eq!(a.len(), 1);
+        assert_eq!(b.len(), 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_spanned_roundtrip() {
+        // `RootItem` isn't `Serialize`/`Deserialize` yet (see its doc comment), so this only
+        // covers what the `serde` feature actually derives today: `Spanned<T>` itself.
+        let spanned = Spanned::new(42u64, 4..6);
+        let json = serde_json::to_string(&spanned).unwrap();
+        let round_tripped: Spanned<u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(spanned, round_tripped);
+    }
 }